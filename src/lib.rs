@@ -1,20 +1,42 @@
-//! Provide a general handler for file descriptor reasources via the `OwnedFd` and `FdRef` types
+//! Provide a general handler for file descriptor reasources via the `OwnedFd` and `BorrowedFd` types
 
 use std::os::unix::io::{IntoRawFd,AsRawFd,FromRawFd,RawFd};
 use std::mem::{forget, transmute};
 use std::io;
 use std::borrow::{Borrow,ToOwned};
+use std::marker::PhantomData;
+use std::num::NonZeroI32;
 use std::ops::{Deref};
 
 extern crate libc;
 
+/*
+ * Duplicate @i, producing a descriptor that has FD_CLOEXEC set so it does not leak across exec.
+ *
+ * Uses F_DUPFD_CLOEXEC where available; on older platforms that reject it with EINVAL we fall back
+ * to a plain dup() followed by F_SETFD.
+ */
 unsafe fn dup(i: RawFd) -> io::Result<RawFd> {
+    let v = libc::fcntl(i, libc::F_DUPFD_CLOEXEC, 0);
+    if v >= 0 {
+        return Ok(v);
+    }
+
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() != Some(libc::EINVAL) {
+        return Err(err);
+    }
+
     let v = libc::dup(i);
     if v < 0 {
-        Err(io::Error::last_os_error())
-    } else {
-        Ok(v)
+        return Err(io::Error::last_os_error());
     }
+    if libc::fcntl(v, libc::F_SETFD, libc::FD_CLOEXEC) < 0 {
+        let e = io::Error::last_os_error();
+        libc::close(v);
+        return Err(e);
+    }
+    Ok(v)
 }
 
 /**
@@ -27,12 +49,30 @@ unsafe fn dup(i: RawFd) -> io::Result<RawFd> {
  *  - has no overhead greater than a RawFd (no buffer, metadata, or other allocations)
  *  - allows use of the borrow system to ensure drop (close) happens only when all users of an
  *    ownedfd have released it.
+ *
+ * The descriptor is stored internally as `NonZeroI32` holding `!fd` (the bitwise NOT of the raw
+ * fd). Every valid descriptor is `>= 0`, so `!fd` lies in `-1 ..= i32::MIN` and is never zero;
+ * this hands `Option<OwnedFd>` (and `Result<OwnedFd, E>` for a zero-sized `E` such as `()`) the
+ * niche it needs to be the same size as a bare `RawFd`.
  */
 pub struct OwnedFd {
-    inner: RawFd,
+    inner: NonZeroI32,
 }
 
 impl OwnedFd {
+    /**
+     * Build an OwnedFd taking ownership of @fd, encoding it into the `NonZeroI32` niche.
+     *
+     * @fd must be a valid descriptor; in particular it must not be `-1`.
+     */
+    fn from_raw(fd: RawFd) -> OwnedFd {
+        // Reachable from the safe `from<T: IntoRawFd>` constructor, so we cannot assume the niche
+        // invariant holds: a misbehaving `IntoRawFd` returning -1 would make `!fd == 0`. Check it
+        // rather than risk UB in release builds.
+        let inner = NonZeroI32::new(!fd).expect("file descriptor -1 is not a valid OwnedFd");
+        OwnedFd { inner }
+    }
+
     /**
      * Given a raw file descriptor that may be owned by another (ie: another data structure might
      * close it), create a Owned version that we have control over (via dup())
@@ -44,18 +84,73 @@ impl OwnedFd {
      *  - @i _must_ be a valid file descriptor (of any kind)
      */
     pub unsafe fn from_unowned_raw(i : RawFd) -> io::Result<OwnedFd> {
-        Ok(OwnedFd { inner: try!(dup(i)) })
+        Ok(OwnedFd::from_raw(dup(i)?))
     }
 
     /**
      * Duplicate this OwnedFd, and allow the error to be detected.
      *
+     * The duplicate has `FD_CLOEXEC` set so it does not leak across an `exec`.
+     *
      * Clone uses this, but panics on error
      */
-    pub fn dup(&self) -> io::Result<OwnedFd> {
+    pub fn try_clone(&self) -> io::Result<OwnedFd> {
         unsafe { OwnedFd::from_unowned_raw(self.as_raw_fd()) }
     }
 
+    /**
+     * Deprecated alias for `try_clone()`.
+     */
+    #[deprecated(note = "renamed to `try_clone` for consistency with std")]
+    pub fn dup(&self) -> io::Result<OwnedFd> {
+        self.try_clone()
+    }
+
+    /**
+     * Set or clear the `FD_CLOEXEC` flag on this descriptor via `fcntl(F_SETFD)`.
+     */
+    pub fn set_cloexec(&self, cloexec: bool) -> io::Result<()> {
+        unsafe {
+            let flags = libc::fcntl(self.as_raw_fd(), libc::F_GETFD);
+            if flags < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let new = if cloexec {
+                flags | libc::FD_CLOEXEC
+            } else {
+                flags & !libc::FD_CLOEXEC
+            };
+            if new != flags && libc::fcntl(self.as_raw_fd(), libc::F_SETFD, new) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+    }
+
+    /**
+     * Report whether the `FD_CLOEXEC` flag is set on this descriptor via `fcntl(F_GETFD)`.
+     */
+    pub fn is_cloexec(&self) -> io::Result<bool> {
+        unsafe {
+            let flags = libc::fcntl(self.as_raw_fd(), libc::F_GETFD);
+            if flags < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(flags & libc::FD_CLOEXEC != 0)
+            }
+        }
+    }
+
+    /**
+     * Borrow this OwnedFd, yielding a `BorrowedFd` whose lifetime is tied to `&self`.
+     *
+     * The borrow checker then enforces that this OwnedFd outlives every use of the returned
+     * borrow, so the fd cannot be closed out from under it.
+     */
+    pub fn as_fd(&self) -> BorrowedFd<'_> {
+        unsafe { BorrowedFd::borrow_raw(self.as_raw_fd()) }
+    }
+
     /**
      * Given a type that impliments `IntoRawFd` construct an OwnedFd.
      *
@@ -65,20 +160,38 @@ impl OwnedFd {
      * specialization stabilizes.
      */
     pub fn from<T: IntoRawFd>(i: T) -> Self {
-        OwnedFd { inner: i.into_raw_fd() }
+        OwnedFd::from_raw(i.into_raw_fd())
+    }
+
+    /**
+     * Explicitly close this OwnedFd, surfacing any error the OS reports.
+     *
+     * `Drop` closes descriptors too, but discards the result of `close(2)`. Some filesystems
+     * (networked, FUSE, etc) only report deferred write errors such as `EIO` or `ENOSPC` at close
+     * time; callers that care about durability should use this and check the result rather than
+     * relying on the drop path.
+     */
+    pub fn close(self) -> io::Result<()> {
+        let fd = self.as_raw_fd();
+        forget(self);
+        if unsafe { libc::close(fd) } < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
     }
 }
 
 impl AsRawFd for OwnedFd {
     fn as_raw_fd(&self) -> RawFd {
-        self.inner
+        !self.inner.get()
     }
 }
 
 
 impl IntoRawFd for OwnedFd {
     fn into_raw_fd(self) -> RawFd {
-        let v = self.inner;
+        let v = self.as_raw_fd();
         forget(self);
         v
     }
@@ -86,32 +199,30 @@ impl IntoRawFd for OwnedFd {
 
 impl FromRawFd for OwnedFd {
     unsafe fn from_raw_fd(fd: RawFd) -> OwnedFd {
-        OwnedFd { inner: fd }
+        OwnedFd::from_raw(fd)
     }
 }
 
 impl Drop for OwnedFd {
     fn drop(&mut self) {
-        unsafe { libc::close(self.inner) };
+        unsafe { libc::close(self.as_raw_fd()) };
     }
 }
 
 impl Clone for OwnedFd {
     fn clone(&self) -> Self {
-        self.dup().unwrap()
+        self.try_clone().unwrap()
     }
 }
 
-/*
- * WARNING: assumes RawFd and (*const _) are the same size! (or at least that RawFd is bounded by
- * isize).
- */
+#[allow(deprecated)]
 impl Borrow<FdRef> for OwnedFd {
     fn borrow(&self) -> &FdRef {
         unsafe { FdRef::from_unowned_raw(self.as_raw_fd()) }
     }
 }
 
+#[allow(deprecated)]
 impl Deref for OwnedFd {
     type Target = FdRef;
     fn deref(&self) -> &Self::Target {
@@ -119,6 +230,96 @@ impl Deref for OwnedFd {
     }
 }
 
+/**
+ * A type that owns (or itself borrows) a file descriptor and can hand out a `BorrowedFd` for it.
+ *
+ * Functions that only need to borrow a descriptor should take `impl AsFd` rather than a `RawFd`:
+ * the caller's ownership invariant is then preserved, and there is no way for the borrow to outlive
+ * the owner or to be used after the fd is closed.
+ */
+pub trait AsFd {
+    fn as_fd(&self) -> BorrowedFd<'_>;
+}
+
+impl AsFd for OwnedFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        OwnedFd::as_fd(self)
+    }
+}
+
+impl<'fd> AsFd for BorrowedFd<'fd> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        *self
+    }
+}
+
+impl AsFd for std::fs::File {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        unsafe { BorrowedFd::borrow_raw(self.as_raw_fd()) }
+    }
+}
+
+impl AsFd for std::net::TcpStream {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        unsafe { BorrowedFd::borrow_raw(self.as_raw_fd()) }
+    }
+}
+
+impl AsFd for std::os::unix::net::UnixStream {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        unsafe { BorrowedFd::borrow_raw(self.as_raw_fd()) }
+    }
+}
+
+/**
+ * A borrowed file descriptor whose lifetime is bound to the owner it was borrowed from.
+ *
+ * `BorrowedFd<'fd>` holds a plain `RawFd` and a `PhantomData<&'fd OwnedFd>` so that the borrow
+ * checker treats it as a shared borrow of the owning `OwnedFd`: the owner cannot be dropped (and
+ * therefore the fd cannot be closed) while any `BorrowedFd` derived from it is still live.
+ *
+ * Because it is `#[repr(transparent)]` over `RawFd`, it has the exact layout of a host `c_int` and
+ * may be passed directly across an FFI boundary that expects a file descriptor.
+ */
+#[derive(Copy, Clone)]
+#[repr(transparent)]
+pub struct BorrowedFd<'fd> {
+    fd: RawFd,
+    _phantom: PhantomData<&'fd OwnedFd>,
+}
+
+impl<'fd> BorrowedFd<'fd> {
+    /**
+     * Construct a `BorrowedFd` from a RawFd. No ownership is taken.
+     *
+     * unsafety:
+     *
+     *  - @fd _must_ be a valid fd for the entirety of the lifetime 'fd
+     *  - the lifetime 'fd must be appropriately bound to the owner of @fd
+     */
+    pub unsafe fn borrow_raw(fd: RawFd) -> BorrowedFd<'fd> {
+        BorrowedFd { fd: fd, _phantom: PhantomData }
+    }
+
+    /**
+     * Duplicate the borrowed descriptor into a fresh `OwnedFd` via `dup`.
+     *
+     * `BorrowedFd` is `Copy`, so the std blanket `ToOwned` is already a plain copy of the borrow;
+     * this is the explicit way to obtain an owned descriptor backed by a new fd.
+     *
+     * The duplicate has `FD_CLOEXEC` set so it does not leak across an `exec`.
+     */
+    pub fn try_clone(&self) -> io::Result<OwnedFd> {
+        unsafe { OwnedFd::from_unowned_raw(self.as_raw_fd()) }
+    }
+}
+
+impl<'fd> AsRawFd for BorrowedFd<'fd> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
 /**
  * A zero-cost (well, very, very, low cost) borrow of an OwnedFd.
  *
@@ -126,11 +327,14 @@ impl Deref for OwnedFd {
  *
  * As a result, it might be slightly larger than a bare RawFd.
  */
+#[deprecated(note = "use the value type `BorrowedFd` (via `OwnedFd::as_fd`) instead; \
+                     `FdRef` relies on transmuting a RawFd into a reference, which is unsound")]
 pub struct FdRef {
     #[doc(hidden)]
     __nothing: ()
 }
 
+#[allow(deprecated)]
 impl FdRef {
     /**
      * Construct a FdRef reference from a RawFd. No ownership is taken.
@@ -145,6 +349,7 @@ impl FdRef {
     }
 }
 
+#[allow(deprecated)]
 impl AsRawFd for FdRef {
     fn as_raw_fd(&self) -> RawFd {
         let i : isize = unsafe { transmute(self) };
@@ -152,6 +357,7 @@ impl AsRawFd for FdRef {
     }
 }
 
+#[allow(deprecated)]
 impl ToOwned for FdRef {
     type Owned = OwnedFd;
     fn to_owned(&self) -> Self::Owned {
@@ -162,8 +368,7 @@ impl ToOwned for FdRef {
 #[cfg(test)]
 mod tests {
     extern crate tempfile;
-    use super::{OwnedFd,FdRef};
-    use std::borrow::Borrow;
+    use super::{OwnedFd,BorrowedFd,AsFd};
     use std::os::unix::io::{AsRawFd};
 
     #[test]
@@ -171,8 +376,42 @@ mod tests {
         let t = tempfile::tempfile().unwrap();
         let fd = OwnedFd::from(t);
 
-        let r : &FdRef = fd.borrow();
+        let b : BorrowedFd = fd.as_fd();
+
+        assert_eq!(b.as_raw_fd(), fd.as_raw_fd());
+        assert!(b.try_clone().unwrap().as_raw_fd() != fd.as_raw_fd());
+    }
+
+    #[test]
+    fn as_fd_generic() {
+        fn raw_of(fd: impl AsFd) -> super::RawFd {
+            fd.as_fd().as_raw_fd()
+        }
+
+        let t = tempfile::tempfile().unwrap();
+        let raw = t.as_raw_fd();
+        assert_eq!(raw_of(t.as_fd()), raw);
+
+        let fd = OwnedFd::from(t);
+        assert_eq!(raw_of(fd.as_fd()), fd.as_raw_fd());
+    }
+
+    #[test]
+    fn try_clone_is_cloexec() {
+        let t = tempfile::tempfile().unwrap();
+        let fd = OwnedFd::from(t);
 
-        assert!(r.to_owned().as_raw_fd() != fd.as_raw_fd());
+        let c = fd.try_clone().unwrap();
+        assert!(c.is_cloexec().unwrap());
+
+        c.set_cloexec(false).unwrap();
+        assert!(!c.is_cloexec().unwrap());
+    }
+
+    #[test]
+    fn explicit_close() {
+        let t = tempfile::tempfile().unwrap();
+        let fd = OwnedFd::from(t);
+        fd.close().unwrap();
     }
 }